@@ -0,0 +1,123 @@
+use std::marker::PhantomData;
+
+use mio::{EventSet, PollOpt};
+
+use super::StreamSocket as Socket;
+use super::stream::{Handshake, Protocol, Stream};
+use handler::Registrator;
+use {Async, EventMachine, Scope};
+
+enum State<C, S: Socket + Handshake, P: Protocol<C>> {
+    // Waiting for the first writable event, which on a non-blocking
+    // `connect()`'d socket means either "connected" or "connect failed".
+    Connecting(S),
+    Connected(Stream<C, S, P>),
+}
+
+/// The client-side counterpart to `Init::accept`: owns a socket returned
+/// from a non-blocking `connect()` that may still be in progress, and
+/// turns it into a normal `Stream` once the connection is established.
+/// This is what lets a `Protocol` be used to dial out, not just to
+/// accept.
+pub struct Connect<C, S: Socket + Handshake, P: Protocol<C>>
+    (State<C, S, P>, PhantomData<*mut C>);
+
+impl<C, S: Socket + Handshake, P: Protocol<C>> Connect<C, S, P> {
+    /// Wraps a socket whose non-blocking `connect()` has been issued but
+    /// not yet confirmed.
+    pub fn new(socket: S) -> Connect<C, S, P> {
+        Connect(State::Connecting(socket), PhantomData)
+    }
+}
+
+impl<C, S: Socket + Handshake, P: Protocol<C>> EventMachine<C>
+    for Connect<C, S, P>
+{
+    fn ready(self, evset: EventSet, scope: &mut Scope<C>)
+        -> Async<Self, Option<Self>>
+    {
+        let Connect(state, _) = self;
+        match state {
+            State::Connecting(mut socket) => {
+                if !evset.is_writable() {
+                    return Async::Continue(
+                        Connect(State::Connecting(socket), PhantomData),
+                        None);
+                }
+                match socket.take_socket_error() {
+                    Ok(()) => {
+                        match Stream::connected(socket, scope) {
+                            Some(stream) => {
+                                // `register()` only ever asked for
+                                // `writable()`; the stream needs its full
+                                // interest (readable included) from here
+                                // on, so push that to the reactor now
+                                // rather than waiting for a `ready()` that
+                                // will never re-register on its own.
+                                stream.reregister(scope);
+                                Async::Continue(
+                                    Connect(State::Connected(stream),
+                                        PhantomData),
+                                    None)
+                            }
+                            None => Async::Stop,
+                        }
+                    }
+                    Err(e) => {
+                        P::connect_failed(e, scope);
+                        Async::Stop
+                    }
+                }
+            }
+            State::Connected(stream) => {
+                stream.ready(evset, scope)
+                .map(|s| Connect(State::Connected(s), PhantomData))
+                .map_result(|opt| opt.map(
+                    |s| Connect(State::Connected(s), PhantomData)))
+            }
+        }
+    }
+
+    fn register(self, reg: &mut Registrator) -> Async<Self, ()> {
+        let Connect(state, _) = self;
+        match state {
+            State::Connecting(socket) => {
+                reg.register(&socket, EventSet::writable(), PollOpt::edge());
+                Async::Continue(
+                    Connect(State::Connecting(socket), PhantomData), ())
+            }
+            State::Connected(stream) => {
+                stream.register(reg)
+                .map(|s| Connect(State::Connected(s), PhantomData))
+            }
+        }
+    }
+
+    fn timeout(self, scope: &mut Scope<C>) -> Async<Self, Option<Self>> {
+        let Connect(state, _) = self;
+        match state {
+            State::Connecting(socket) => Async::Continue(
+                Connect(State::Connecting(socket), PhantomData), None),
+            State::Connected(stream) => {
+                stream.timeout(scope)
+                .map(|s| Connect(State::Connected(s), PhantomData))
+                .map_result(|opt| opt.map(
+                    |s| Connect(State::Connected(s), PhantomData)))
+            }
+        }
+    }
+
+    fn wakeup(self, scope: &mut Scope<C>) -> Async<Self, Option<Self>> {
+        let Connect(state, _) = self;
+        match state {
+            State::Connecting(socket) => Async::Continue(
+                Connect(State::Connecting(socket), PhantomData), None),
+            State::Connected(stream) => {
+                stream.wakeup(scope)
+                .map(|s| Connect(State::Connected(s), PhantomData))
+                .map_result(|opt| opt.map(
+                    |s| Connect(State::Connected(s), PhantomData)))
+            }
+        }
+    }
+}
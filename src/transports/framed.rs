@@ -0,0 +1,171 @@
+use std::io;
+use std::marker::PhantomData;
+
+use netbuf::Buf;
+
+use super::StreamSocket as Socket;
+use super::stream::{Protocol, Transport};
+use {Async, Scope};
+
+/// Turns bytes sitting in `Transport::input()` into whole frames. `decode`
+/// is called repeatedly; it should consume exactly one frame's worth of
+/// bytes from the front of `buf` and return it, or leave `buf` untouched
+/// and return `Ok(None)` if a full frame isn't buffered yet (e.g. a
+/// 4-byte big-endian length header followed by that many payload bytes).
+pub trait Decoder {
+    type Frame;
+
+    fn decode(&mut self, buf: &mut Buf)
+        -> Result<Option<Self::Frame>, io::Error>;
+}
+
+/// The write-side counterpart of `Decoder`: serializes a frame onto the
+/// back of `buf`. `FrameTransport::send_frame` takes care of the length header;
+/// `encode` only needs to write the payload.
+pub trait Encoder {
+    type Frame;
+
+    fn encode(&mut self, frame: Self::Frame, buf: &mut Buf);
+}
+
+/// A message-oriented counterpart to `Protocol<C>`: instead of raw bytes,
+/// implementors receive whole, already-decoded frames. `Framed<C, Self>`
+/// wraps this in a `Protocol<C>` that can be dropped straight into
+/// `Stream<C, S, Framed<C, M>>`.
+pub trait FrameMachine<C>: Sized {
+    type Codec: Decoder + Encoder<Frame=<Self::Codec as Decoder>::Frame>
+        + Default;
+
+    fn accepted(scope: &mut Scope<C>) -> Option<Self>;
+    fn frame_received(self,
+        frame: <Self::Codec as Decoder>::Frame,
+        trans: &mut FrameTransport<Self::Codec>,
+        scope: &mut Scope<C>) -> Async<Self, ()>;
+    fn data_transferred(self,
+        _trans: &mut FrameTransport<Self::Codec>,
+        _scope: &mut Scope<C>) -> Async<Self, ()>
+    {
+        Async::Continue(self, ())
+    }
+    fn error_happened(self, _err: io::Error, _scope: &mut Scope<C>) {}
+    fn eof_received(self, _scope: &mut Scope<C>) {}
+    fn idle_timeout(self, _scope: &mut Scope<C>) {}
+    fn timeout(self, _scope: &mut Scope<C>) -> Async<Self, ()> {
+        Async::Continue(self, ())
+    }
+    fn wakeup(self, _scope: &mut Scope<C>) -> Async<Self, ()> {
+        Async::Continue(self, ())
+    }
+}
+
+/// The frame-oriented equivalent of `Transport`: lets a `FrameMachine`
+/// send a reply while handling a `frame_received` callback.
+pub struct FrameTransport<'a, E: Encoder + 'a> {
+    codec: &'a mut E,
+    outbuf: &'a mut Buf,
+}
+
+impl<'a, E: Encoder> FrameTransport<'a, E> {
+    /// Encodes `frame` and appends it to the outgoing buffer as a
+    /// 4-byte big-endian length header followed by the payload.
+    pub fn send_frame(&mut self, frame: E::Frame) {
+        let mut payload = Buf::new();
+        self.codec.encode(frame, &mut payload);
+        let len = payload.len() as u32;
+        self.outbuf.extend(&[
+            (len >> 24) as u8, (len >> 16) as u8,
+            (len >> 8) as u8, len as u8,
+        ]);
+        self.outbuf.extend(&payload[..]);
+    }
+}
+
+/// Adapts a `FrameMachine` into a `Protocol<C>`, turning the byte-stream
+/// `Transport` into a message-oriented one. This removes the hand-rolled
+/// "is there a complete frame yet" bookkeeping that protocols built
+/// directly on `Stream` otherwise have to repeat in `data_received`.
+pub struct Framed<C, M: FrameMachine<C>> {
+    codec: M::Codec,
+    fsm: M,
+    marker: PhantomData<*mut C>,
+}
+
+impl<C, M: FrameMachine<C>> Protocol<C> for Framed<C, M> {
+    fn accepted<S: Socket>(_conn: &mut S, scope: &mut Scope<C>)
+        -> Option<Self>
+    {
+        let fsm = match M::accepted(scope) {
+            Some(fsm) => fsm,
+            None => return None,
+        };
+        Some(Framed {
+            codec: M::Codec::default(),
+            fsm: fsm,
+            marker: PhantomData,
+        })
+    }
+
+    fn data_received(self, trans: &mut Transport, scope: &mut Scope<C>)
+        -> Async<Self, ()>
+    {
+        let Framed { mut codec, fsm, marker } = self;
+        let mut monad = Async::Continue(fsm, ());
+        loop {
+            match codec.decode(trans.input()) {
+                Ok(Some(frame)) => {
+                    monad = async_try!(monad.and_then(|fsm| {
+                        let mut ftrans = FrameTransport {
+                            codec: &mut codec,
+                            outbuf: trans.output(),
+                        };
+                        fsm.frame_received(frame, &mut ftrans, scope)
+                    }));
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    monad.done(|fsm| fsm.error_happened(e, scope));
+                    return Async::Stop;
+                }
+            }
+        }
+        monad.map(|fsm| Framed { codec: codec, fsm: fsm, marker: marker })
+    }
+
+    fn data_transferred(self, trans: &mut Transport, scope: &mut Scope<C>)
+        -> Async<Self, ()>
+    {
+        let Framed { mut codec, fsm, marker } = self;
+        let result = {
+            let mut ftrans = FrameTransport {
+                codec: &mut codec,
+                outbuf: trans.output(),
+            };
+            fsm.data_transferred(&mut ftrans, scope)
+        };
+        result.map(|fsm| Framed { codec: codec, fsm: fsm, marker: marker })
+    }
+
+    fn error_happened(self, err: io::Error, scope: &mut Scope<C>) {
+        self.fsm.error_happened(err, scope)
+    }
+
+    fn eof_received(self, scope: &mut Scope<C>) {
+        self.fsm.eof_received(scope)
+    }
+
+    fn idle_timeout(self, scope: &mut Scope<C>) {
+        self.fsm.idle_timeout(scope)
+    }
+
+    fn timeout(self, scope: &mut Scope<C>) -> Async<Self, ()> {
+        let Framed { codec, fsm, marker } = self;
+        fsm.timeout(scope)
+        .map(|fsm| Framed { codec: codec, fsm: fsm, marker: marker })
+    }
+
+    fn wakeup(self, scope: &mut Scope<C>) -> Async<Self, ()> {
+        let Framed { codec, fsm, marker } = self;
+        fsm.wakeup(scope)
+        .map(|fsm| Framed { codec: codec, fsm: fsm, marker: marker })
+    }
+}
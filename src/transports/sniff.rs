@@ -0,0 +1,144 @@
+use std::io;
+use std::marker::PhantomData;
+
+use super::StreamSocket as Socket;
+use super::stream::{Protocol, Transport};
+use {Async, Scope};
+
+/// Lets a `Protocol` inspect the first bytes on the wire and decide it's
+/// actually the wrong protocol for this connection -- the classic case
+/// being choosing between HTTP/1.1 and HTTP/2 by peeking for the
+/// 14-byte connection preface `"PRI * HTTP/2.0"` before consuming
+/// anything.
+pub trait Sniff<C>: Sized {
+    /// The protocol to hand the connection off to when `sniff` decides
+    /// to switch.
+    type Upgrade: Protocol<C>;
+
+    /// Looks at, but must not consume, the bytes already buffered in
+    /// `Transport::input()`. Returns `Some(true)` to switch to
+    /// `Upgrade`, `Some(false)` to keep using `Self`, or `None` if not
+    /// enough bytes have arrived yet to tell.
+    fn sniff(&self, buf: &[u8]) -> Option<bool>;
+
+    /// Builds the `Upgrade` protocol once `sniff` has decided to switch.
+    fn upgrade(self, scope: &mut Scope<C>) -> Option<Self::Upgrade>;
+}
+
+enum State<C, P: Sniff<C> + Protocol<C>> {
+    Sniffing(P),
+    Upgraded(P::Upgrade),
+}
+
+/// A `Protocol<C>` adapter that peeks at the first bytes received and
+/// replaces itself with a different `Protocol` if `P::sniff` says so,
+/// without dropping the connection or its buffers: `Inner`'s
+/// `inbuf`/`outbuf`, socket and readiness flags are untouched by the
+/// switch, so the new protocol's first `data_received` sees whatever was
+/// already buffered. This enables ALPN-less protocol negotiation and
+/// post-handshake upgrades (WebSocket, HTTP/2 h2c) entirely inside the
+/// `Stream` FSM.
+pub struct Sniffing<C, P: Sniff<C> + Protocol<C>>
+    (State<C, P>, PhantomData<*mut C>);
+
+impl<C, P: Sniff<C> + Protocol<C>> Protocol<C> for Sniffing<C, P> {
+    fn accepted<S: Socket>(conn: &mut S, scope: &mut Scope<C>)
+        -> Option<Self>
+    {
+        Protocol::accepted(conn, scope)
+        .map(|p| Sniffing(State::Sniffing(p), PhantomData))
+    }
+
+    fn data_received(self, trans: &mut Transport, scope: &mut Scope<C>)
+        -> Async<Self, ()>
+    {
+        let Sniffing(state, marker) = self;
+        match state {
+            State::Sniffing(p) => {
+                match p.sniff(&trans.input()[..]) {
+                    Some(true) => match p.upgrade(scope) {
+                        Some(up) => {
+                            up.data_received(trans, scope)
+                            .map(|up| Sniffing(State::Upgraded(up), marker))
+                        }
+                        None => Async::Stop,
+                    },
+                    Some(false) => {
+                        p.data_received(trans, scope)
+                        .map(|p| Sniffing(State::Sniffing(p), marker))
+                    }
+                    None => {
+                        // Not enough bytes yet to decide; wait for more.
+                        Async::Continue(
+                            Sniffing(State::Sniffing(p), marker), ())
+                    }
+                }
+            }
+            State::Upgraded(up) => {
+                up.data_received(trans, scope)
+                .map(|up| Sniffing(State::Upgraded(up), marker))
+            }
+        }
+    }
+
+    fn data_transferred(self, trans: &mut Transport, scope: &mut Scope<C>)
+        -> Async<Self, ()>
+    {
+        let Sniffing(state, marker) = self;
+        match state {
+            State::Sniffing(p) => {
+                p.data_transferred(trans, scope)
+                .map(|p| Sniffing(State::Sniffing(p), marker))
+            }
+            State::Upgraded(up) => {
+                up.data_transferred(trans, scope)
+                .map(|up| Sniffing(State::Upgraded(up), marker))
+            }
+        }
+    }
+
+    fn error_happened(self, err: io::Error, scope: &mut Scope<C>) {
+        match self.0 {
+            State::Sniffing(p) => p.error_happened(err, scope),
+            State::Upgraded(up) => up.error_happened(err, scope),
+        }
+    }
+
+    fn eof_received(self, scope: &mut Scope<C>) {
+        match self.0 {
+            State::Sniffing(p) => p.eof_received(scope),
+            State::Upgraded(up) => up.eof_received(scope),
+        }
+    }
+
+    fn idle_timeout(self, scope: &mut Scope<C>) {
+        match self.0 {
+            State::Sniffing(p) => p.idle_timeout(scope),
+            State::Upgraded(up) => up.idle_timeout(scope),
+        }
+    }
+
+    fn timeout(self, scope: &mut Scope<C>) -> Async<Self, ()> {
+        let Sniffing(state, marker) = self;
+        match state {
+            State::Sniffing(p) => {
+                p.timeout(scope).map(|p| Sniffing(State::Sniffing(p), marker))
+            }
+            State::Upgraded(up) => {
+                up.timeout(scope).map(|up| Sniffing(State::Upgraded(up), marker))
+            }
+        }
+    }
+
+    fn wakeup(self, scope: &mut Scope<C>) -> Async<Self, ()> {
+        let Sniffing(state, marker) = self;
+        match state {
+            State::Sniffing(p) => {
+                p.wakeup(scope).map(|p| Sniffing(State::Sniffing(p), marker))
+            }
+            State::Upgraded(up) => {
+                up.wakeup(scope).map(|up| Sniffing(State::Upgraded(up), marker))
+            }
+        }
+    }
+}
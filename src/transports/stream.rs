@@ -1,32 +1,87 @@
 use std::marker::PhantomData;
 use std::io;
 use std::io::ErrorKind::{WouldBlock, Interrupted};
+use std::net::Shutdown;
 
 use netbuf::Buf;
-use time::SteadyTime;
+use time::{SteadyTime, Duration};
 use mio::{EventSet, PollOpt};
+use mio::tcp::TcpStream;
 
 use super::StreamSocket as Socket;
 use super::accept::Init;
 use handler::{Registrator};
 use {Async, EventMachine, Scope};
 
+/// The deadline of the idle-timeout reactor timer armed by `Stream`. While
+/// one is stored, no further idle timer is armed; whether a firing is due
+/// is decided from the last activity plus the idle duration, not from
+/// this stored instant.
 pub struct Timeout(pub SteadyTime);
 
+/// Sockets that need to complete an out-of-band negotiation before any
+/// application data exists (currently: `TlsStream`'s TLS handshake).
+/// Plain sockets are ready the moment they're accepted, hence the
+/// no-op defaults.
+pub trait Handshake {
+    fn pump(&mut self) -> io::Result<()> { Ok(()) }
+    fn handshake_done(&mut self) -> io::Result<bool> { Ok(true) }
+}
+
+impl Handshake for TcpStream {}
+
+// Which callback to invoke once a deferred handshake completes: the
+// socket came from `Init::accept` (a listener) or from `Connect` (this
+// side dialled out), so `Protocol::accepted` or `Protocol::connected`
+// respectively is the one the protocol actually expects.
+enum HandshakeOrigin {
+    Accepted,
+    Connected,
+}
+
+enum Fsm<P> {
+    // Waiting for `Handshake::handshake_done` before `accepted`/
+    // `connected` can be called.
+    Handshaking(HandshakeOrigin),
+    Ready(P),
+}
+
 struct Inner<S: Socket> {
     socket: S,
     inbuf: Buf,
     outbuf: Buf,
     writable: bool,
     readable: bool,
+    // Read backpressure: once `inbuf` reaches `high_mark`, readable
+    // interest is dropped from `interest` until the protocol drains it
+    // back to `low_mark` or below. Defaults leave reads unbounded.
+    low_mark: usize,
+    high_mark: usize,
+    interest: EventSet,
+    // Idle-timeout bookkeeping: `idle` is the configured duration (if
+    // any), `last_activity` resets on every `data_received`/
+    // `data_transferred`, and `deadline` is the instant the currently
+    // armed reactor timeout targets.
+    idle: Option<Duration>,
+    last_activity: SteadyTime,
+    deadline: Option<Timeout>,
+    // Half-close: `shutdown_write` is the protocol's request to stop
+    // writing once `outbuf` drains; `write_shutdown` records that
+    // `Shutdown::Write` has actually been done, so it's only done once.
+    shutdown_write: bool,
+    write_shutdown: bool,
 }
 
-pub struct Stream<C, S: Socket, P: Protocol<C>>
-    (Inner<S>, P, PhantomData<*mut C>);
+pub struct Stream<C, S: Socket + Handshake, P: Protocol<C>>
+    (Inner<S>, Fsm<P>, PhantomData<*mut C>);
 
 pub struct Transport<'a> {
     inbuf: &'a mut Buf,
     outbuf: &'a mut Buf,
+    low_mark: &'a mut usize,
+    high_mark: &'a mut usize,
+    idle: &'a mut Option<Duration>,
+    shutdown_write: &'a mut bool,
 }
 
 
@@ -35,16 +90,88 @@ impl<S: Socket> Inner<S> {
         Transport {
             inbuf: &mut self.inbuf,
             outbuf: &mut self.outbuf,
+            low_mark: &mut self.low_mark,
+            high_mark: &mut self.high_mark,
+            idle: &mut self.idle,
+            shutdown_write: &mut self.shutdown_write,
+        }
+    }
+
+    // Arms the reactor timeout for the current idle deadline, unless one
+    // is already in flight: re-arming on every bit of activity would
+    // stack up a fresh reactor timer on essentially every `ready()` call
+    // on a busy connection. The one outstanding timer is left to fire on
+    // schedule; `timeout()` re-arms it for the remaining time if activity
+    // happened since it was set, rather than this function doing so.
+    fn rearm_idle_timeout<C>(&mut self, scope: &mut Scope<C>) {
+        if self.deadline.is_some() {
+            return;
+        }
+        if let Some(idle) = self.idle {
+            let deadline = self.last_activity + idle;
+            scope.timeout_at(deadline);
+            self.deadline = Some(Timeout(deadline));
         }
     }
 }
 
-impl<C, S: Socket, P: Protocol<C>> Init<S, C> for Stream<C, S, P> {
+impl<C, S: Socket + Handshake, P: Protocol<C>> Stream<C, S, P> {
+    /// Re-applies `Inner::interest` to the reactor. `ready()` does the same
+    /// itself, through `Scope::reregister`, whenever a watermark crossing or
+    /// a half-close changes the interest set, so the change actually
+    /// reaches mio rather than just updating in-memory state; `Connect`
+    /// calls this once, right after a
+    /// socket finishes connecting, to move registration from
+    /// `writable()`-only to the stream's full interest.
+    pub(crate) fn reregister(&self, scope: &mut Scope<C>) {
+        scope.reregister(&self.0.socket, self.0.interest, PollOpt::edge());
+    }
+
+    /// Builds a `Stream` around a socket whose outbound `connect()` has
+    /// just completed, invoking `Protocol::connected` rather than
+    /// `Protocol::accepted`. Used by `Connect` once it observes the
+    /// socket became writable and carries no pending `SO_ERROR`.
+    pub fn connected(mut conn: S, scope: &mut Scope<C>) -> Option<Self> {
+        let fsm = match conn.handshake_done() {
+            Ok(true) => match Protocol::connected(&mut conn, scope) {
+                Some(p) => Fsm::Ready(p),
+                None => return None,
+            },
+            Ok(false) => Fsm::Handshaking(HandshakeOrigin::Connected),
+            Err(_) => return None,
+        };
+
+        Some(Stream(Inner {
+            socket: conn,
+            inbuf: Buf::new(),
+            outbuf: Buf::new(),
+            readable: false,
+            writable: true,
+            low_mark: 0,
+            high_mark: usize::max_value(),
+            interest: EventSet::all(),
+            idle: None,
+            last_activity: SteadyTime::now(),
+            deadline: None,
+            shutdown_write: false,
+            write_shutdown: false,
+        }, fsm, PhantomData))
+    }
+}
+
+impl<C, S: Socket + Handshake, P: Protocol<C>> Init<S, C> for Stream<C, S, P> {
     fn accept(mut conn: S, scope: &mut Scope<C>) -> Option<Self>
     {
-        let protocol = match Protocol::accepted(&mut conn, scope) {
-            Some(x) => x,
-            None => return None
+        let fsm = match conn.handshake_done() {
+            Ok(true) => match Protocol::accepted(&mut conn, scope) {
+                Some(p) => Fsm::Ready(p),
+                None => return None,
+            },
+            // A socket like `TlsStream` may still be mid-handshake right
+            // after accept; defer `Protocol::accepted` until `ready()`
+            // observes the handshake has finished.
+            Ok(false) => Fsm::Handshaking(HandshakeOrigin::Accepted),
+            Err(_) => return None,
         };
 
         Some(Stream(Inner {
@@ -53,15 +180,54 @@ impl<C, S: Socket, P: Protocol<C>> Init<S, C> for Stream<C, S, P> {
             outbuf: Buf::new(),
             readable: false,
             writable: true,   // Accepted socket is immediately writable
-        }, protocol, PhantomData))
+            low_mark: 0,
+            high_mark: usize::max_value(),
+            interest: EventSet::all(),
+            idle: None,
+            last_activity: SteadyTime::now(),
+            deadline: None,
+            shutdown_write: false,
+            write_shutdown: false,
+        }, fsm, PhantomData))
     }
 }
 
-impl<C, S: Socket, P: Protocol<C>> EventMachine<C> for Stream<C, S, P> {
+impl<C, S: Socket + Handshake, P: Protocol<C>> EventMachine<C> for Stream<C, S, P> {
     fn ready(self, evset: EventSet, scope: &mut Scope<C>)
         -> Async<Self, Option<Self>>
     {
         let Stream(mut stream, fsm, _) = self;
+        let prev_interest = stream.interest;
+
+        let fsm = match fsm {
+            Fsm::Handshaking(origin) => {
+                match stream.socket.pump()
+                    .and_then(|()| stream.socket.handshake_done())
+                {
+                    Ok(true) => {
+                        let accepted = match origin {
+                            HandshakeOrigin::Accepted => Protocol::accepted(
+                                &mut stream.socket, scope),
+                            HandshakeOrigin::Connected => Protocol::connected(
+                                &mut stream.socket, scope),
+                        };
+                        match accepted {
+                            Some(p) => p,
+                            None => return Async::Stop,
+                        }
+                    }
+                    Ok(false) => {
+                        return Async::Continue(
+                            Stream(stream, Fsm::Handshaking(origin),
+                                PhantomData),
+                            None);
+                    }
+                    Err(_) => return Async::Stop,
+                }
+            }
+            Fsm::Ready(fsm) => fsm,
+        };
+
         let mut monad = Async::Continue(fsm, ());
         if evset.is_writable() && stream.outbuf.len() > 0 {
             stream.writable = true;
@@ -72,6 +238,7 @@ impl<C, S: Socket, P: Protocol<C>> EventMachine<C> for Stream<C, S, P> {
                         return Async::Stop;
                     }
                     Ok(_) => {
+                        stream.last_activity = SteadyTime::now();
                         monad = async_try!(monad.and_then(|f| {
                             f.data_transferred(
                                 &mut stream.transport(), scope)
@@ -89,7 +256,7 @@ impl<C, S: Socket, P: Protocol<C>> EventMachine<C> for Stream<C, S, P> {
                 }
             }
         }
-        if evset.is_readable() {
+        if evset.is_readable() && stream.inbuf.len() < stream.high_mark {
             stream.readable = true;
             loop {
                 match stream.inbuf.read_from(&mut stream.socket) {
@@ -98,10 +265,19 @@ impl<C, S: Socket, P: Protocol<C>> EventMachine<C> for Stream<C, S, P> {
                         return Async::Stop;
                     }
                     Ok(_) => {
+                        stream.last_activity = SteadyTime::now();
                         monad = async_try!(monad.and_then(|f| {
                             f.data_received(
                                 &mut stream.transport(), scope)
                         }));
+                        if stream.inbuf.len() >= stream.high_mark {
+                            // Slow protocol, fast peer: stop reading and
+                            // drop readable interest rather than let
+                            // `inbuf` grow without bound.
+                            stream.interest =
+                                stream.interest - EventSet::readable();
+                            break;
+                        }
                     }
                     Err(ref e) if e.kind() == WouldBlock => {
                         stream.readable = false;
@@ -115,6 +291,10 @@ impl<C, S: Socket, P: Protocol<C>> EventMachine<C> for Stream<C, S, P> {
                 }
             }
         }
+        if !stream.interest.is_readable() && stream.inbuf.len() <= stream.low_mark {
+            // The protocol drained `inbuf` back down; resume reading.
+            stream.interest = stream.interest | EventSet::readable();
+        }
         if stream.writable && stream.outbuf.len() > 0 {
             while stream.outbuf.len() > 0 {
                 match stream.outbuf.write_to(&mut stream.socket) {
@@ -123,6 +303,7 @@ impl<C, S: Socket, P: Protocol<C>> EventMachine<C> for Stream<C, S, P> {
                         return Async::Stop;
                     }
                     Ok(_) => {
+                        stream.last_activity = SteadyTime::now();
                         monad = async_try!(monad.and_then(|f| {
                             f.data_transferred(
                                 &mut stream.transport(), scope)
@@ -140,27 +321,99 @@ impl<C, S: Socket, P: Protocol<C>> EventMachine<C> for Stream<C, S, P> {
                 }
             }
         }
+        if stream.shutdown_write && !stream.write_shutdown
+            && stream.outbuf.len() == 0
+        {
+            match stream.socket.shutdown(Shutdown::Write) {
+                Ok(()) => {
+                    stream.write_shutdown = true;
+                    // Nothing left to write; readable interest stays so
+                    // `data_received`/`eof_received` keep working until
+                    // the peer closes its side too.
+                    stream.interest = stream.interest - EventSet::writable();
+                }
+                Err(e) => {
+                    monad.done(|fsm| fsm.error_happened(e, scope));
+                    return Async::Stop;
+                }
+            }
+        }
+        if stream.interest != prev_interest {
+            // Actually push the watermark-driven (or half-close) interest
+            // change to mio; without this the socket stays registered for
+            // whatever `register()` set up once at add-time and crossing
+            // `high_mark` only updates in-memory state, so the peer's
+            // bytes keep arriving with no pending edge left to read them.
+            scope.reregister(&stream.socket, stream.interest, PollOpt::edge());
+        }
+        stream.rearm_idle_timeout(scope);
         monad
-        .map(|fsm| Stream(stream, fsm, PhantomData))
+        .map(|fsm| Stream(stream, Fsm::Ready(fsm), PhantomData))
         .map_result(|()| None)
     }
 
     fn register(self, reg: &mut Registrator) -> Async<Self, ()> {
-        reg.register(&self.0.socket, EventSet::all(), PollOpt::edge());
+        reg.register(&self.0.socket, self.0.interest, PollOpt::edge());
         Async::Continue(self, ())
     }
 
     fn timeout(self, scope: &mut Scope<C>) -> Async<Self, Option<Self>> {
-        let Stream(stream, fsm, _) = self;
+        let Stream(mut stream, fsm, _) = self;
+        let fsm = match fsm {
+            Fsm::Ready(fsm) => fsm,
+            // Nothing to time out yet; the handshake has its own
+            // readiness-driven progress.
+            Fsm::Handshaking(origin) => {
+                return Async::Continue(
+                    Stream(stream, Fsm::Handshaking(origin), PhantomData),
+                    None);
+            }
+        };
+
+        // Only treat this firing as the idle timer if we're actually the
+        // ones with a deadline currently armed; otherwise there's no
+        // outstanding idle timeout to have fired (e.g. `idle` was set but
+        // hasn't been armed by a `ready()` yet) and this is some other
+        // reactor timeout the protocol is waiting on via its own
+        // `timeout()`, which must still get a chance to run below.
+        if let Some(idle) = stream.idle {
+            if stream.deadline.is_some() {
+                // Recompute from `last_activity`, which keeps moving as
+                // long as there's traffic -- comparing against the frozen
+                // `Timeout` stored in `deadline` would make every firing
+                // look overdue the moment wall-clock passes the instant
+                // it was first armed for, regardless of activity since.
+                let deadline = stream.last_activity + idle;
+                if SteadyTime::now() >= deadline {
+                    fsm.idle_timeout(scope);
+                    return Async::Stop;
+                }
+                // Activity happened since this timer was armed; re-arm
+                // for the remaining time. This firing still needs to
+                // reach the protocol's own `timeout()` below, though --
+                // it may well be why the reactor woke us at all.
+                stream.deadline = None;
+                stream.rearm_idle_timeout(scope);
+            }
+        }
+
         async_try!(fsm.timeout(scope))
-        .map(|fsm| Stream(stream, fsm, PhantomData))
+        .map(|fsm| Stream(stream, Fsm::Ready(fsm), PhantomData))
         .map_result(|()| None)
     }
 
     fn wakeup(self, scope: &mut Scope<C>) -> Async<Self, Option<Self>> {
         let Stream(stream, fsm, _) = self;
+        let fsm = match fsm {
+            Fsm::Ready(fsm) => fsm,
+            Fsm::Handshaking(origin) => {
+                return Async::Continue(
+                    Stream(stream, Fsm::Handshaking(origin), PhantomData),
+                    None);
+            }
+        };
         async_try!(fsm.wakeup(scope))
-        .map(|fsm| Stream(stream, fsm, PhantomData))
+        .map(|fsm| Stream(stream, Fsm::Ready(fsm), PhantomData))
         .map_result(|()| None)
     }
 }
@@ -168,6 +421,14 @@ impl<C, S: Socket, P: Protocol<C>> EventMachine<C> for Stream<C, S, P> {
 pub trait Protocol<C>: Sized {
     fn accepted<S: Socket>(conn: &mut S, scope: &mut Scope<C>)
         -> Option<Self>;
+    /// Like `accepted`, but for a socket this side connected to rather
+    /// than one a listener accepted. Defaults to `accepted`, since most
+    /// protocols don't care which side dialled the connection.
+    fn connected<S: Socket>(conn: &mut S, scope: &mut Scope<C>)
+        -> Option<Self>
+    {
+        Protocol::accepted(conn, scope)
+    }
     fn data_received(self, trans: &mut Transport, scope: &mut Scope<C>)
         -> Async<Self, ()>;
     fn data_transferred(self, _trans: &mut Transport, _scope: &mut Scope<C>)
@@ -178,6 +439,16 @@ pub trait Protocol<C>: Sized {
     fn error_happened(self, _err: io::Error, _scope: &mut Scope<C>) {}
     fn eof_received(self, _scope: &mut Scope<C>) {}
 
+    /// Like `error_happened`, but for a non-blocking `connect()` that
+    /// failed before any `Self` existed to receive it.
+    fn connect_failed(_err: io::Error, _scope: &mut Scope<C>) {}
+
+    /// Called once the connection has been idle (no `data_received` or
+    /// `data_transferred`) for the duration set via
+    /// `Transport::set_idle_timeout`. The connection is closed
+    /// afterwards regardless of what this does, same as `eof_received`.
+    fn idle_timeout(self, _scope: &mut Scope<C>) {}
+
     fn timeout(self, _scope: &mut Scope<C>) -> Async<Self, ()> {
         Async::Continue(self, ())
     }
@@ -193,4 +464,30 @@ impl<'a> Transport<'a> {
     pub fn output<'x>(&'x mut self) -> &'x mut Buf {
         self.outbuf
     }
+
+    /// Sets the read backpressure watermarks: once `inbuf` reaches
+    /// `high`, `ready()` stops reading and drops readable interest even
+    /// if more data is pending; once the protocol drains `inbuf` back to
+    /// `low` or below, readable interest is restored. Defaults to
+    /// unbounded reads.
+    pub fn set_read_watermark(&mut self, low: usize, high: usize) {
+        *self.low_mark = low;
+        *self.high_mark = high;
+    }
+
+    /// Arms an idle-disconnect: if `idle` passes with no
+    /// `data_received`/`data_transferred`, `Protocol::idle_timeout`
+    /// fires and the connection is closed. Pass `None` to disable it.
+    pub fn set_idle_timeout(&mut self, idle: Option<Duration>) {
+        *self.idle = idle;
+    }
+
+    /// Requests a graceful half-close: once `output()` has been fully
+    /// flushed, the write side of the socket is shut down (the peer
+    /// sees EOF) while readable interest stays registered, so
+    /// `data_received`/`eof_received` keep working until the peer
+    /// closes its side too.
+    pub fn shutdown_write(&mut self) {
+        *self.shutdown_write = true;
+    }
 }
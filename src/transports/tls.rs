@@ -0,0 +1,188 @@
+use std::io;
+use std::io::{Read, Write};
+use std::io::ErrorKind::{WouldBlock, UnexpectedEof, InvalidData};
+use std::net::Shutdown;
+use std::sync::Arc;
+
+use mio::{Evented, EventSet, Poll, PollOpt, Token};
+use rustls::{ServerConfig, ServerSession, Session};
+
+use super::StreamSocket as Socket;
+use super::stream::Handshake;
+use Scope;
+
+/// Lets `TlsStream::accept` pull the shared `ServerConfig` for a listener
+/// straight out of the per-connection `Scope` context, the same way other
+/// accept-time state (seeds, registries) is threaded through `C`, rather
+/// than requiring every accept call site to carry the `Arc` by hand.
+pub trait TlsConfig {
+    fn tls_config(&self) -> &Arc<ServerConfig>;
+}
+
+/// A `Socket` adapter that terminates TLS on top of an inner plaintext
+/// transport, so it can be dropped into `Stream<C, TlsStream<S>, P>`
+/// without any protocol-level changes.
+///
+/// A TLS session needs to read in order to make progress on a write (and
+/// vice versa), both during the handshake and afterwards (renegotiation,
+/// alerts), so `Read`/`Write` can't just forward to the socket: every call
+/// pumps ciphertext in both directions until the socket would block both
+/// ways (`read` before taking plaintext out of the session, `write` after
+/// handing plaintext to it), the same approach tokio-rustls takes.
+pub struct TlsStream<S: Socket> {
+    socket: S,
+    session: ServerSession,
+    handshaking: bool,
+}
+
+impl<S: Socket> TlsStream<S> {
+    /// Wraps an already-accepted, non-blocking socket in a server-side TLS
+    /// session built from a shared `config`.
+    pub fn server(socket: S, config: Arc<ServerConfig>) -> TlsStream<S> {
+        TlsStream {
+            socket: socket,
+            session: ServerSession::new(&config),
+            handshaking: true,
+        }
+    }
+
+    /// Wraps a freshly accepted, non-blocking socket in a server-side TLS
+    /// session built from the `ServerConfig` carried in `scope`'s context,
+    /// so `Init::accept` doesn't need the config plumbed in by hand --
+    /// the call site just needs `C: TlsConfig`.
+    pub fn accept<C: TlsConfig>(socket: S, scope: &Scope<C>) -> TlsStream<S> {
+        TlsStream::server(socket, scope.tls_config().clone())
+    }
+
+    /// Feeds ciphertext in both directions until the socket would block
+    /// both ways, translating "session wants read during a write" (or
+    /// vice versa) into simply making no progress this round rather than
+    /// an error: the caller just waits for the next readiness event.
+    pub fn pump(&mut self) -> io::Result<()> {
+        loop {
+            let mut progress = false;
+
+            if self.session.wants_read() {
+                match self.session.read_tls(&mut self.socket) {
+                    Ok(0) => {
+                        return Err(io::Error::new(UnexpectedEof,
+                            "eof while reading tls record"));
+                    }
+                    Ok(_) => {
+                        self.session.process_new_packets()
+                            .map_err(|e| io::Error::new(InvalidData, e))?;
+                        progress = true;
+                    }
+                    Err(ref e) if e.kind() == WouldBlock => {}
+                    Err(e) => return Err(e),
+                }
+            }
+
+            if self.session.wants_write() {
+                match self.session.write_tls(&mut self.socket) {
+                    Ok(_) => progress = true,
+                    Err(ref e) if e.kind() == WouldBlock => {}
+                    Err(e) => return Err(e),
+                }
+            }
+
+            if self.handshaking && !self.session.is_handshaking() {
+                self.handshaking = false;
+            }
+
+            if !progress {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Forwards `SO_ERROR` from the underlying socket, the same way a
+    /// plain `Socket` does; TLS has no notion of this itself.
+    pub fn take_socket_error(&self) -> io::Result<()> {
+        self.socket.take_socket_error()
+    }
+
+    /// Shuts down the inner transport. Note this does *not* send a TLS
+    /// `close_notify`; protocols that need a clean TLS-level shutdown
+    /// should do so explicitly before dropping the connection.
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.socket.shutdown(how)
+    }
+}
+
+impl<S: Socket> Read for TlsStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.pump()?;
+        if self.handshaking {
+            return Err(io::Error::new(WouldBlock,
+                "tls handshake in progress"));
+        }
+        match self.session.read(buf) {
+            Ok(n) if n > 0 => Ok(n),
+            // rustls returns `Ok(0)` both for a clean TLS-level close and
+            // simply "handshake done but no complete plaintext record
+            // buffered yet" -- a genuine socket EOF is caught earlier, by
+            // `pump`'s `read_tls`, and surfaces from there as an error
+            // rather than reaching this match. The pump above has already
+            // taken in whatever ciphertext the socket had, so treat
+            // `Ok(0)` the same as `WouldBlock` and ask the caller to wait
+            // for the next readiness event, instead of reporting it as a
+            // spurious EOF.
+            Ok(_) => {
+                Err(io::Error::new(WouldBlock, "no plaintext available yet"))
+            }
+            Err(ref e) if e.kind() == WouldBlock => {
+                Err(io::Error::new(WouldBlock, "no plaintext available yet"))
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl<S: Socket> Write for TlsStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.handshaking {
+            self.pump()?;
+            if self.handshaking {
+                return Err(io::Error::new(WouldBlock,
+                    "tls handshake in progress"));
+            }
+        }
+        let n = self.session.write(buf)?;
+        self.pump()?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.session.flush()?;
+        self.pump()
+    }
+}
+
+impl<S: Socket> Handshake for TlsStream<S> {
+    fn pump(&mut self) -> io::Result<()> {
+        TlsStream::pump(self)
+    }
+
+    fn handshake_done(&mut self) -> io::Result<bool> {
+        Ok(!self.handshaking)
+    }
+}
+
+impl<S: Socket> Evented for TlsStream<S> {
+    fn register(&self, poll: &Poll, token: Token, interest: EventSet,
+        opts: PollOpt) -> io::Result<()>
+    {
+        self.socket.register(poll, token, interest, opts)
+    }
+
+    fn reregister(&self, poll: &Poll, token: Token, interest: EventSet,
+        opts: PollOpt) -> io::Result<()>
+    {
+        self.socket.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        self.socket.deregister(poll)
+    }
+}